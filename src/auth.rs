@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+// 用于签发和校验 JWT 的共享密钥。生产环境应从配置中注入，这里为演示内置一个固定值。
+const JWT_SECRET: &[u8] = b"axum-ws-live-secret";
+
+// Claims携带经过认证的用户身份，在 WebSocket 升级握手阶段从 JWT 中解出。
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Claims {
+    pub username: String,
+    pub exp: usize,
+}
+
+impl Claims {
+    pub fn new(username: impl Into<String>, exp: usize) -> Self {
+        Self {
+            username: username.into(),
+            exp,
+        }
+    }
+
+    // sign将 claims 编码为一个 HS256 的 JWT 字符串，主要供客户端与测试使用。
+    pub fn sign(&self) -> Result<String, jsonwebtoken::errors::Error> {
+        encode(
+            &Header::default(),
+            self,
+            &EncodingKey::from_secret(JWT_SECRET),
+        )
+    }
+}
+
+// Claims既是 WebSocket 升级的提取器，也是拒绝未认证连接的地方。
+// token 可来自 `Authorization: Bearer <jwt>` 头，或升级请求的 `token` query 参数。
+#[async_trait]
+impl<S> FromRequestParts<S> for Claims
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let token = bearer_token(parts)
+            .or_else(|| query_token(parts))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        let data = decode::<Claims>(
+            &token,
+            &DecodingKey::from_secret(JWT_SECRET),
+            &Validation::default(),
+        )
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+        Ok(data.claims)
+    }
+}
+
+fn bearer_token(parts: &Parts) -> Option<String> {
+    parts
+        .headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.to_string())
+}
+
+fn query_token(parts: &Parts) -> Option<String> {
+    let query = parts.uri.query()?;
+    serde_urlencoded::from_str::<HashMap<String, String>>(query)
+        .ok()?
+        .remove("token")
+}
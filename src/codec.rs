@@ -0,0 +1,118 @@
+use std::fmt;
+
+use axum::extract::ws::Message;
+
+use crate::Msg;
+
+// Codec是 `Msg` 与 WebSocket 帧之间的可替换序列化边界。
+// 它把原先写死在 `TryFrom`/`TryInto` 里的 JSON 约定抽象出来，
+// 这样部署方可以在不触碰路由逻辑的前提下换用紧凑的二进制协议。
+pub trait Codec: fmt::Debug + Send + Sync + 'static {
+    fn encode(&self, msg: &Msg) -> Result<Message, CodecError>;
+    fn decode(&self, msg: &Message) -> Result<Msg, CodecError>;
+}
+
+// JsonCodec复用既有的 JSON 约定：文本帧承载 `serde_json` 序列化后的消息。
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode(&self, msg: &Msg) -> Result<Message, CodecError> {
+        Ok(Message::Text(serde_json::to_string(msg)?))
+    }
+
+    fn decode(&self, msg: &Message) -> Result<Msg, CodecError> {
+        match msg {
+            Message::Text(s) => Ok(serde_json::from_str(s)?),
+            Message::Binary(b) => Ok(serde_json::from_slice(b)?),
+            _ => Err(CodecError::UnsupportedFrame),
+        }
+    }
+}
+
+// MsgPackCodec用 MessagePack 把消息编码进二进制帧，适合带宽敏感的部署。
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MsgPackCodec;
+
+impl Codec for MsgPackCodec {
+    fn encode(&self, msg: &Msg) -> Result<Message, CodecError> {
+        Ok(Message::Binary(rmp_serde::to_vec(msg)?))
+    }
+
+    fn decode(&self, msg: &Message) -> Result<Msg, CodecError> {
+        match msg {
+            Message::Binary(b) => Ok(rmp_serde::from_slice(b)?),
+            _ => Err(CodecError::UnsupportedFrame),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Json(serde_json::Error),
+    MsgPackEncode(rmp_serde::encode::Error),
+    MsgPackDecode(rmp_serde::decode::Error),
+    UnsupportedFrame,
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Json(e) => write!(f, "json codec error: {e}"),
+            CodecError::MsgPackEncode(e) => write!(f, "msgpack encode error: {e}"),
+            CodecError::MsgPackDecode(e) => write!(f, "msgpack decode error: {e}"),
+            CodecError::UnsupportedFrame => write!(f, "unsupported frame type for this codec"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<serde_json::Error> for CodecError {
+    fn from(e: serde_json::Error) -> Self {
+        CodecError::Json(e)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for CodecError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        CodecError::MsgPackEncode(e)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for CodecError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        CodecError::MsgPackDecode(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MsgData;
+
+    #[test]
+    fn json_codec_round_trips() {
+        let codec = JsonCodec;
+        let msg = Msg::message("room1", "username1", "hello");
+        let frame = codec.encode(&msg).unwrap();
+        assert!(matches!(frame, Message::Text(_)));
+        assert_eq!(codec.decode(&frame).unwrap(), msg);
+    }
+
+    #[test]
+    fn msgpack_codec_round_trips() {
+        let codec = MsgPackCodec;
+        let msg = Msg::message("room1", "username1", "hello");
+        let frame = codec.encode(&msg).unwrap();
+        assert!(matches!(frame, Message::Binary(_)));
+        assert_eq!(codec.decode(&frame).unwrap(), msg);
+    }
+
+    #[test]
+    fn msgpack_codec_rejects_text_frames() {
+        let codec = MsgPackCodec;
+        let err = codec.decode(&Message::Text("{}".into())).unwrap_err();
+        assert!(matches!(err, CodecError::UnsupportedFrame));
+    }
+}
@@ -1,3 +1,5 @@
+mod auth;
+mod codec;
 mod msg;
 
 use std::sync::Arc;
@@ -9,12 +11,25 @@ use axum::{
 };
 use dashmap::{DashMap, DashSet};
 use futures::{Sink, SinkExt, Stream, StreamExt};
+pub use auth::Claims;
+pub use codec::{Codec, CodecError, JsonCodec, MsgPackCodec};
 pub use msg::{Msg, MsgData};
 use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 use tracing::warn;
 
 const CAPACITY: usize = 64;
 
+// LagPolicy决定当某个慢消费者落后于广播队列（`RecvError::Lagged`）时如何处理。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LagPolicy {
+    // 跳过被丢弃的消息并继续，同时给客户端发送一条系统通知。
+    #[default]
+    Skip,
+    // 当连接落后时直接关闭它。
+    Close,
+}
+
 #[derive(Debug)]
 struct State {
     // for a given user, how many rooms they're in
@@ -25,6 +40,12 @@ struct State {
     room_users: DashMap<String, DashSet<String>>,
     // tx是一个消息发送器，用于向其他线程发送消息
     tx: broadcast::Sender<Arc<Msg>>,
+    // codec决定了消息在 WebSocket 帧上的编解码格式（JSON、MessagePack 等）。
+    codec: Arc<dyn Codec>,
+    // lag_policy决定慢消费者落后时的处理方式。
+    lag_policy: LagPolicy,
+    // capacity是广播通道的缓冲容量，保留下来便于运行时自省。
+    capacity: usize,
 }
 
 impl Default for State {
@@ -34,6 +55,9 @@ impl Default for State {
             user_rooms: Default::default(),
             room_users: Default::default(),
             tx,
+            codec: Arc::new(JsonCodec),
+            lag_policy: LagPolicy::default(),
+            capacity: CAPACITY,
         }
     }
 }
@@ -47,6 +71,26 @@ impl ChatState {
         Self(Default::default())
     }
 
+    // builder返回一个带有 build-time 默认值的构造器，供运营方调整缓冲容量与 lag 策略。
+    pub fn builder() -> ChatStateBuilder {
+        ChatStateBuilder::default()
+    }
+
+    // with_codec构造一个使用指定线上编解码格式的 `ChatState`。
+    pub fn with_codec(codec: Arc<dyn Codec>) -> Self {
+        Self::builder().codec(codec).build()
+    }
+
+    // capacity暴露该状态广播通道的缓冲容量。
+    pub fn capacity(&self) -> usize {
+        self.0.capacity
+    }
+
+    // lag_policy暴露慢消费者落后时生效的策略。
+    pub fn lag_policy(&self) -> LagPolicy {
+        self.0.lag_policy
+    }
+
     pub fn get_user_rooms(&self, username: &str) -> Vec<String> {
         self.0
             .user_rooms
@@ -64,37 +108,206 @@ impl ChatState {
     }
 }
 
+// ChatStateBuilder按需配置广播缓冲容量、lag 策略与线上编解码格式，
+// 未显式设置的字段沿用与 `ChatState::new()` 相同的 build-time 默认值。
+#[derive(Debug, Clone)]
+pub struct ChatStateBuilder {
+    capacity: usize,
+    lag_policy: LagPolicy,
+    codec: Arc<dyn Codec>,
+}
+
+impl Default for ChatStateBuilder {
+    fn default() -> Self {
+        Self {
+            capacity: CAPACITY,
+            lag_policy: LagPolicy::default(),
+            codec: Arc::new(JsonCodec),
+        }
+    }
+}
+
+impl ChatStateBuilder {
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn lag_policy(mut self, lag_policy: LagPolicy) -> Self {
+        self.lag_policy = lag_policy;
+        self
+    }
+
+    pub fn codec(mut self, codec: Arc<dyn Codec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn build(self) -> ChatState {
+        let (tx, _rx) = broadcast::channel(self.capacity);
+        ChatState(Arc::new(State {
+            user_rooms: Default::default(),
+            room_users: Default::default(),
+            tx,
+            codec: self.codec,
+            lag_policy: self.lag_policy,
+            capacity: self.capacity,
+        }))
+    }
+}
+
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
-    // claims: Claims,
+    claims: Claims,
     Extension(state): Extension<ChatState>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+    ws.on_upgrade(|socket| handle_socket(socket, state, claims.username))
 }
 
-pub async fn handle_socket<S>(socket: S, state: ChatState)
+// Membership记录一帧入站消息对该连接已加入房间集合的影响，
+// 以便在广播之后决定是否发送 presence 快照或回显 leave 通知。
+enum Membership {
+    Joined(String),
+    Left(String),
+    Unchanged,
+}
+
+pub async fn handle_socket<S>(socket: S, state: ChatState, username: String)
 where
     S: Stream<Item = Result<Message, axum::Error>> + Sink<Message> + Send + 'static,
 {
     let mut rx = state.0.tx.subscribe();
     let (mut sender, mut receiver) = socket.split();
 
+    // rooms this particular connection has joined. The send task consults it so a
+    // client only ever receives traffic for rooms it is actually in.
+    let rooms: Arc<DashSet<String>> = Arc::new(DashSet::new());
+
+    // direct per-connection send path, used for server-originated messages (the
+    // presence snapshot, system notices) that must reach only this client.
+    let (direct_tx, mut direct_rx) = tokio::sync::mpsc::unbounded_channel::<Arc<Msg>>();
+
     let state1 = state.clone();
+    let recv_username = username.clone();
+    let recv_rooms = rooms.clone();
+    let recv_codec = state.0.codec.clone();
     let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            match msg {
-                Message::Text(msg) => {
-                    handle_message(msg.as_str().try_into().unwrap(), state1.0.clone()).await;
+        while let Some(Ok(frame)) = receiver.next().await {
+            // both text and binary frames are handed to the codec; anything else
+            // (ping/pong/close) is ignored here.
+            if !matches!(frame, Message::Text(_) | Message::Binary(_)) {
+                continue;
+            }
+            let mut msg = match recv_codec.decode(&frame) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("decode msg failed: {e}");
+                    continue;
+                }
+            };
+            // the client cannot spoof another identity: the room bookkeeping
+            // always uses the username proven by the JWT at upgrade time.
+            msg.username = recv_username.clone();
+            let membership = match msg.data {
+                MsgData::Join => {
+                    recv_rooms.insert(msg.room.clone());
+                    Membership::Joined(msg.room.clone())
+                }
+                MsgData::Leave => Membership::Left(msg.room.clone()),
+                _ => Membership::Unchanged,
+            };
+            handle_message(msg, state1.0.clone()).await;
+
+            match membership {
+                // right after a successful join, tell this client who is already in
+                // the room. This goes only to the joining connection, not the broadcast.
+                Membership::Joined(room) => {
+                    let users = state1.get_room_users(&room);
+                    let presence = Msg::presence(&room, &recv_username, users);
+                    if direct_tx.send(Arc::new(presence)).is_err() {
+                        break;
+                    }
                 }
-                _ => (),
+                // echo the leave back to the leaver over the direct path before the
+                // room drops out of its joined set — otherwise the send-side room
+                // filter would swallow the leaver's own leave notice.
+                Membership::Left(room) => {
+                    let leave = Msg::leave(&room, &recv_username);
+                    if direct_tx.send(Arc::new(leave)).is_err() {
+                        break;
+                    }
+                    recv_rooms.remove(&room);
+                }
+                Membership::Unchanged => {}
             }
         }
     });
 
+    let send_rooms = rooms.clone();
+    let send_codec = state.0.codec.clone();
+    let send_username = username.clone();
+    let lag_policy = state.0.lag_policy;
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            let data = msg.as_ref().try_into().unwrap();
-            if sender.send(Message::Text(data)).await.is_err() {
+        let mut direct_open = true;
+        loop {
+            // server-originated messages on the direct path bypass the room filter
+            // and are always delivered to this client. The direct path is polled
+            // first, so a presence snapshot precedes broadcast frames already queued.
+            let msg = tokio::select! {
+                biased;
+                direct = direct_rx.recv(), if direct_open => match direct {
+                    Some(msg) => {
+                        match send_codec.encode(&msg) {
+                            Ok(frame) => {
+                                if sender.send(frame).await.is_err() {
+                                    warn!("send msg failed");
+                                    break;
+                                }
+                            }
+                            Err(e) => warn!("encode msg failed: {e}"),
+                        }
+                        continue;
+                    }
+                    None => {
+                        direct_open = false;
+                        continue;
+                    }
+                },
+                res = rx.recv() => match res {
+                    Ok(msg) => msg,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(n)) => match lag_policy {
+                        LagPolicy::Skip => {
+                            warn!("connection for {send_username} lagged, dropped {n} messages");
+                            let notice = Msg::system(
+                                "",
+                                &send_username,
+                                &format!("lagged, {n} messages dropped"),
+                            );
+                            if let Ok(frame) = send_codec.encode(&notice) {
+                                let _ = sender.send(frame).await;
+                            }
+                            continue;
+                        }
+                        LagPolicy::Close => {
+                            warn!("closing lagged connection for {send_username} ({n} behind)");
+                            break;
+                        }
+                    },
+                },
+            };
+            // drop traffic for rooms this connection has not joined.
+            if !send_rooms.contains(&msg.room) {
+                continue;
+            }
+            let frame = match send_codec.encode(&msg) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    warn!("encode msg failed: {e}");
+                    continue;
+                }
+            };
+            if sender.send(frame).await.is_err() {
                 warn!("send msg failed");
                 break;
             }
@@ -107,13 +320,12 @@ where
         _v2 = &mut send_task =>  recv_task.abort(),
     }
 
-    // this user has left. Should send a leave message to all rooms
-    // usually we can get username from auth header, here we just use "fake_user"
-    let username = "fake_user";
+    // this user has left. Should send a leave message to all rooms.
+    // the identity comes from the authenticated claims, not the client payload.
     warn!("connection for {username} closed");
 
-    for room in state.get_user_rooms(username) {
-        if let Err(e) = state.0.tx.send(Arc::new(Msg::leave(&room, username))) {
+    for room in state.get_user_rooms(&username) {
+        if let Err(e) = state.0.tx.send(Arc::new(Msg::leave(&room, &username))) {
             warn!("send leave msg failed: {e}");
         }
     }
@@ -282,6 +494,222 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn client_should_not_receive_other_room_messages() -> Result<()> {
+        let (mut client1, socket1) = create_fake_connection();
+        let (mut client2, socket2) = create_fake_connection();
+        let state = ChatState::new();
+
+        let state1 = state.clone();
+        tokio::spawn(async move {
+            handle_socket(socket1, state1, "username1".into()).await;
+        });
+        let state1 = state.clone();
+        tokio::spawn(async move {
+            handle_socket(socket2, state1, "username2".into()).await;
+        });
+
+        client1.send(Message::Text((&Msg::join("room1", "username1")).try_into()?))?;
+        client2.send(Message::Text((&Msg::join("room2", "username2")).try_into()?))?;
+
+        // each client only sees the join for the room it is in.
+        verify(&mut client1, "room1", "username1", MsgData::Join).await?;
+        verify(&mut client2, "room2", "username2", MsgData::Join).await?;
+
+        // a message into room2 must never reach the room1 client.
+        let msg = Msg::message("room2", "username2", "hello");
+        client2.send(Message::Text((&msg).try_into()?))?;
+        verify(
+            &mut client2,
+            "room2",
+            "username2",
+            MsgData::Message("hello".into()),
+        )
+        .await?;
+
+        // client1 may still receive its own presence snapshot, so gate the leak
+        // check on the payload: no frame for room2 must ever reach it.
+        while let Ok(Some(Message::Text(frame))) =
+            tokio::time::timeout(std::time::Duration::from_millis(100), client1.recv()).await
+        {
+            let msg = Msg::try_from(frame.as_str())?;
+            assert_ne!(msg.room, "room2", "room1 client received room2 traffic");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn join_receives_presence_snapshot() -> Result<()> {
+        let state = ChatState::new();
+
+        // seed the room with an existing user.
+        let (mut client1, socket1) = create_fake_connection();
+        let state1 = state.clone();
+        tokio::spawn(async move {
+            handle_socket(socket1, state1, "username1".into()).await;
+        });
+        client1.send(Message::Text((&Msg::join("room1", "username1")).try_into()?))?;
+        verify(&mut client1, "room1", "username1", MsgData::Join).await?;
+
+        // a second user joins and should immediately learn who is already present.
+        let (mut client2, socket2) = create_fake_connection();
+        let state1 = state.clone();
+        tokio::spawn(async move {
+            handle_socket(socket2, state1, "username2".into()).await;
+        });
+        client2.send(Message::Text((&Msg::join("room1", "username2")).try_into()?))?;
+
+        let mut snapshot = None;
+        while let Ok(Some(Message::Text(frame))) =
+            tokio::time::timeout(std::time::Duration::from_millis(200), client2.recv()).await
+        {
+            let msg = Msg::try_from(frame.as_str())?;
+            if let MsgData::Presence { users } = msg.data {
+                snapshot = Some(users);
+                break;
+            }
+        }
+
+        let mut users = snapshot.expect("joining client should receive a presence snapshot");
+        users.sort();
+        assert_eq!(users, &["username1", "username2"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_socket_round_trips_msgpack_binary_frames() -> Result<()> {
+        let codec = MsgPackCodec;
+        let state = ChatState::with_codec(Arc::new(codec));
+
+        let (mut client1, socket1) = create_fake_connection();
+        let state1 = state.clone();
+        tokio::spawn(async move {
+            handle_socket(socket1, state1, "username1".into()).await;
+        });
+        let (mut client2, socket2) = create_fake_connection();
+        let state1 = state.clone();
+        tokio::spawn(async move {
+            handle_socket(socket2, state1, "username2".into()).await;
+        });
+
+        // clients speak the binary protocol on the wire.
+        client1.send(codec.encode(&Msg::join("room1", "username1"))?)?;
+        client2.send(codec.encode(&Msg::join("room1", "username2"))?)?;
+
+        let msg = Msg::message("room1", "username1", "hello");
+        client1.send(codec.encode(&msg)?)?;
+
+        // the second client receives room traffic as MessagePack binary frames.
+        let mut saw_message = false;
+        while let Ok(Some(frame)) =
+            tokio::time::timeout(std::time::Duration::from_millis(200), client2.recv()).await
+        {
+            assert!(matches!(frame, Message::Binary(_)), "expected binary frames");
+            let decoded = codec.decode(&frame)?;
+            if let MsgData::Message(text) = &decoded.data {
+                assert_eq!(decoded.room, "room1");
+                assert_eq!(decoded.username, "username1");
+                assert_eq!(text, "hello");
+                saw_message = true;
+                break;
+            }
+        }
+        assert!(saw_message, "msgpack client should receive the room message");
+        Ok(())
+    }
+
+    #[test]
+    fn builder_surfaces_defaults_and_overrides() {
+        let default = ChatState::new();
+        assert_eq!(default.capacity(), 64);
+        assert_eq!(default.lag_policy(), LagPolicy::Skip);
+
+        let tuned = ChatState::builder()
+            .capacity(8)
+            .lag_policy(LagPolicy::Close)
+            .build();
+        assert_eq!(tuned.capacity(), 8);
+        assert_eq!(tuned.lag_policy(), LagPolicy::Close);
+    }
+
+    // flood_room向 room1 灌入大量消息，制造一个落后的慢消费者。
+    async fn flood_room(state: &ChatState, count: usize) -> Result<()> {
+        let (mut producer, socket) = create_fake_connection();
+        let state1 = state.clone();
+        tokio::spawn(async move {
+            handle_socket(socket, state1, "producer".into()).await;
+        });
+        producer.send(Message::Text((&Msg::join("room1", "producer")).try_into()?))?;
+        for i in 0..count {
+            let msg = Msg::message("room1", "producer", &format!("msg {i}"));
+            producer.send(Message::Text((&msg).try_into()?))?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lag_skip_policy_notifies_client() -> Result<()> {
+        let state = ChatState::builder()
+            .capacity(1)
+            .lag_policy(LagPolicy::Skip)
+            .build();
+        let (mut client, socket) = create_fake_connection();
+        let state1 = state.clone();
+        tokio::spawn(async move {
+            handle_socket(socket, state1, "listener".into()).await;
+        });
+        client.send(Message::Text((&Msg::join("room1", "listener")).try_into()?))?;
+
+        flood_room(&state, 256).await?;
+
+        // a slow listener under the skip policy keeps its connection and eventually
+        // receives a system notice about the dropped messages.
+        let mut saw_notice = false;
+        while let Ok(Some(Message::Text(frame))) =
+            tokio::time::timeout(std::time::Duration::from_millis(200), client.recv()).await
+        {
+            let msg = Msg::try_from(frame.as_str())?;
+            if matches!(&msg.data, MsgData::System(s) if s.contains("lagged")) {
+                saw_notice = true;
+                break;
+            }
+        }
+        assert!(saw_notice, "skip policy should notify the lagging client");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lag_close_policy_drops_connection() -> Result<()> {
+        let state = ChatState::builder()
+            .capacity(1)
+            .lag_policy(LagPolicy::Close)
+            .build();
+        let (mut client, socket) = create_fake_connection();
+        let state1 = state.clone();
+        tokio::spawn(async move {
+            handle_socket(socket, state1, "listener".into()).await;
+        });
+        client.send(Message::Text((&Msg::join("room1", "listener")).try_into()?))?;
+
+        flood_room(&state, 256).await?;
+
+        // under the close policy the send side terminates, so the client stream ends.
+        let mut closed = false;
+        loop {
+            match tokio::time::timeout(std::time::Duration::from_millis(200), client.recv()).await {
+                Ok(Some(_)) => continue,
+                Ok(None) => {
+                    closed = true;
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        assert!(closed, "close policy should terminate the lagging connection");
+        Ok(())
+    }
+
     async fn prepare_connections() -> Result<(FakeClient<Message>, FakeClient<Message>, ChatState)>
     {
         let (mut client1, socket1) = create_fake_connection();
@@ -291,12 +719,12 @@ mod tests {
         // mimic server behavior
         let state1 = state.clone();
         tokio::spawn(async move {
-            handle_socket(socket1, state1).await;
+            handle_socket(socket1, state1, "username1".into()).await;
         });
 
         let state1 = state.clone();
         tokio::spawn(async move {
-            handle_socket(socket2, state1).await;
+            handle_socket(socket2, state1, "username2".into()).await;
         });
 
         let msg1 = &Msg::join("room1", "username1");
@@ -320,11 +748,17 @@ mod tests {
         username: &str,
         data: MsgData,
     ) -> Result<()> {
-        if let Some(Message::Text(msg1)) = client.recv().await {
+        // skip server-originated frames (presence snapshots, system notices) so the
+        // assertions target the chat traffic under test.
+        while let Some(Message::Text(msg1)) = client.recv().await {
             let msg = Msg::try_from(msg1.as_str())?;
+            if matches!(msg.data, MsgData::Presence { .. } | MsgData::System(_)) {
+                continue;
+            }
             assert_eq!(msg.room, room);
             assert_eq!(msg.username, username);
             assert_eq!(msg.data, data);
+            break;
         }
         Ok::<_, anyhow::Error>(())
     }
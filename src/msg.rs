@@ -16,6 +16,10 @@ pub enum MsgData {
     Join,
     Leave,
     Message(String),
+    // Presence是服务端在用户成功加入后发给该连接的房间在场快照。
+    Presence { users: Vec<String> },
+    // System承载服务端发起的通知；服务端只在慢消费者丢弃消息（LagPolicy::Skip）时发出它。
+    System(String),
 }
 
 //  `TryFrom`  是 Rust 语言中的一个 trait，用于定义类型之间的转换。
@@ -92,4 +96,12 @@ impl Msg {
             MsgData::Message(message.into()),
         )
     }
+
+    pub fn presence(room: &str, username: &str, users: Vec<String>) -> Self {
+        Msg::new(room.into(), username.into(), MsgData::Presence { users })
+    }
+
+    pub fn system(room: &str, username: &str, notice: &str) -> Self {
+        Msg::new(room.into(), username.into(), MsgData::System(notice.into()))
+    }
 }